@@ -1,10 +1,13 @@
 extern crate blake2b_simd;
+extern crate hex;
 extern crate memmap;
 extern crate os_pipe;
+extern crate rayon;
 #[macro_use]
 extern crate structopt;
 
-use blake2b_simd::{Hash, Params, State};
+use blake2b_simd::{blake2bp, Hash, Params, State};
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
@@ -34,8 +37,45 @@ struct Opt {
     #[structopt(long = "mmap")]
     /// Read input with memory mapping.
     mmap: bool,
+
+    #[structopt(long = "parallel")]
+    /// Hash large regular files across multiple threads, using the BLAKE2bp tree mode.
+    /// Has no effect on files smaller than the parallel threshold, or on standard input
+    /// unless --mmap is also given.
+    parallel: bool,
+
+    #[structopt(long = "num-threads")]
+    /// The size of the thread pool used by --parallel. Defaults to the number of logical CPUs.
+    num_threads: Option<usize>,
+
+    #[structopt(long = "key-file", parse(from_os_str))]
+    /// Hash in keyed (MAC) mode, using up to 64 bytes read from this file as the key.
+    key_file: Option<PathBuf>,
+
+    #[structopt(long = "salt")]
+    /// A 16-byte salt, given as a hex string.
+    salt: Option<String>,
+
+    #[structopt(long = "personal")]
+    /// A 16-byte personalization string, given as a hex string.
+    personal: Option<String>,
+
+    #[structopt(long = "buffer-size", hidden = true)]
+    /// Override the size of the read buffer used by the sequential (non-mmap) path, in bytes.
+    /// Exists so benchmarks can sweep this value; most users should leave it alone.
+    buffer_size: Option<usize>,
 }
 
+// Default read buffer sizes for the sequential path, tuned separately for pipes and regular
+// files: a 4 MiB buffer measures ~8% faster than a small one on large sequential files, but
+// that's wasted memory for the common case of hashing a short-lived pipe.
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+const FILE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+// Below this size, the overhead of spinning up a thread pool and splitting work into leaves
+// outweighs the gains from BLAKE2bp, so --parallel falls back to single-threaded BLAKE2b.
+const PARALLEL_THRESHOLD: u64 = 256 * 1024;
+
 enum Input {
     Stdin,
     File(File),
@@ -60,46 +100,154 @@ fn open_input(path: &Path, mmap: bool) -> io::Result<Input> {
     })
 }
 
-fn hash_one(input: Input, hash_length: usize) -> io::Result<Hash> {
-    let mut state = Params::new().hash_length(hash_length).to_state();
+fn hex16(flag: &str, hex_str: &str) -> io::Result<[u8; 16]> {
+    let bytes = hex::decode(hex_str)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", flag, e)))?;
+    if bytes.len() != 16 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} must be exactly 16 bytes, got {}", flag, bytes.len()),
+        ));
+    }
+    let mut array = [0; 16];
+    array.copy_from_slice(&bytes);
+    Ok(array)
+}
+
+fn read_key_file(path: &Path) -> io::Result<Vec<u8>> {
+    let key = fs::read(path)?;
+    if key.len() > 64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--key-file must be at most 64 bytes",
+        ));
+    }
+    Ok(key)
+}
+
+fn build_state(opt: &Opt) -> io::Result<State> {
+    let mut params = Params::new();
+    params.hash_length(opt.length_bits / 8);
+    if let Some(key_file) = &opt.key_file {
+        params.key(&read_key_file(key_file)?);
+    }
+    if let Some(salt) = &opt.salt {
+        params.salt(&hex16("--salt", salt)?);
+    }
+    if let Some(personal) = &opt.personal {
+        params.personal(&hex16("--personal", personal)?);
+    }
+    Ok(params.to_state())
+}
+
+fn build_parallel_state(opt: &Opt) -> io::Result<blake2bp::State> {
+    let mut params = blake2bp::Params::new();
+    params.hash_length(opt.length_bits / 8);
+    if let Some(key_file) = &opt.key_file {
+        params.key(&read_key_file(key_file)?);
+    }
+    if let Some(salt) = &opt.salt {
+        params.salt(&hex16("--salt", salt)?);
+    }
+    if let Some(personal) = &opt.personal {
+        params.personal(&hex16("--personal", personal)?);
+    }
+    Ok(params.to_state())
+}
+
+fn should_parallelize(opt: &Opt, len: u64) -> bool {
+    opt.parallel && len >= PARALLEL_THRESHOLD
+}
+
+fn hash_one(input: Input, opt: &Opt) -> io::Result<Hash> {
     match input {
         Input::Stdin => {
+            // Unmapped standard input's length is unknown ahead of time, so it always takes the
+            // sequential path, regardless of --parallel.
+            let mut state = build_state(opt)?;
             let stdin = io::stdin();
-            let mut stdin = stdin.lock();
-            read_write_all(&mut stdin, &mut state)?;
+            let stdin = stdin.lock();
+            let buffer_size = opt.buffer_size.unwrap_or(PIPE_BUFFER_SIZE);
+            read_write_all(stdin, &mut state, buffer_size)?;
+            Ok(state.finalize())
         }
         Input::File(mut file) => {
-            read_write_all(&mut file, &mut state)?;
+            let len = file.metadata()?.len();
+            if should_parallelize(opt, len) {
+                hash_parallel_reader(&mut file, opt)
+            } else {
+                let mut state = build_state(opt)?;
+                let buffer_size = opt.buffer_size.unwrap_or(FILE_BUFFER_SIZE);
+                read_write_all(file, &mut state, buffer_size)?;
+                Ok(state.finalize())
+            }
         }
         Input::Mmap(mmap) => {
-            state.update(&mmap);
+            if should_parallelize(opt, mmap.len() as u64) {
+                hash_parallel_slice(&mmap, opt)
+            } else {
+                let mut state = build_state(opt)?;
+                state.update(&mmap);
+                Ok(state.finalize())
+            }
         }
     }
+}
+
+fn build_thread_pool(num_threads: Option<usize>) -> io::Result<rayon::ThreadPool> {
+    let mut builder = rayon::ThreadPoolBuilder::new();
+    if let Some(num_threads) = num_threads {
+        builder = builder.num_threads(num_threads);
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn hash_parallel_slice(input: &[u8], opt: &Opt) -> io::Result<Hash> {
+    let pool = build_thread_pool(opt.num_threads)?;
+    let mut state = build_parallel_state(opt)?;
+    pool.install(|| state.update(input));
+    Ok(state.finalize())
+}
+
+fn hash_parallel_reader<R: Read>(reader: &mut R, opt: &Opt) -> io::Result<Hash> {
+    // Big chunks keep the rayon overhead small relative to the work handed to each leaf.
+    let mut buf = vec![0; 1024 * 1024];
+    let pool = build_thread_pool(opt.num_threads)?;
+    let mut state = build_parallel_state(opt)?;
+    pool.install(|| -> io::Result<()> {
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            state.update(&buf[..n]);
+        }
+    })?;
     Ok(state.finalize())
 }
 
-fn read_write_all<R: Read>(reader: &mut R, writer: &mut State) -> io::Result<()> {
-    // Why 32728 (2^15)? Basically, that's just what coreutils uses. When I benchmark lots of
-    // different sizes, a 4 MiB heap buffer actually seems to be the best size, possibly 8% faster
-    // than this. Though repeatedly hashing a gigabyte of random data might not reflect real world
-    // usage, who knows. At the end of the day, when we really care about speed, we're going to use
-    // --mmap and skip buffering entirely. The main goal of this program is to compare the
-    // underlying hash implementations (which is to say OpenSSL, which coreutils links against),
-    // and to get an honest comparison we might as well use the same buffer size.
-    let mut buf = [0; 32768];
+fn read_write_all<R: Read>(reader: R, writer: &mut State, buffer_size: usize) -> io::Result<()> {
+    // Read through a BufReader and hash straight out of its internal buffer via fill_buf/consume,
+    // rather than copying each read into a small stack buffer first. At the end of the day, when
+    // we really care about speed, we're going to use --mmap and skip buffering entirely; this is
+    // for the sequential path, which still dominates when mmap isn't an option (e.g. stdin).
+    let mut reader = io::BufReader::with_capacity(buffer_size, reader);
     loop {
-        let n = reader.read(&mut buf)?;
+        let buf = reader.fill_buf()?;
+        let n = buf.len();
         if n == 0 {
             return Ok(());
         }
-        writer.write_all(&buf[..n])?;
+        writer.update(buf);
+        reader.consume(n);
     }
 }
 
 fn do_path(path: &Path, opt: &Opt) -> io::Result<Hash> {
     let input = open_input(path, opt.mmap)?;
-    let hash_length = opt.length_bits / 8;
-    hash_one(input, hash_length)
+    hash_one(input, opt)
 }
 
 fn main() {
@@ -110,6 +258,13 @@ fn main() {
         exit(1);
     }
 
+    if opt.buffer_size == Some(0) {
+        // A zero-capacity BufReader always reports EOF on the first fill_buf, so reads would
+        // silently stop after zero bytes instead of erroring.
+        eprintln!("Invalid --buffer-size: must be greater than 0.");
+        exit(1);
+    }
+
     let mut did_error = false;
     for path in &opt.input {
         let path_str = path.to_string_lossy();
@@ -125,3 +280,68 @@ fn main() {
         exit(1);
     }
 }
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn hex16_decodes_valid_salt() {
+        let hex_str = "000102030405060708090a0b0c0d0e0f";
+        let decoded = hex16("--salt", hex_str).unwrap();
+        assert_eq!(decoded, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn hex16_rejects_wrong_length() {
+        let err = hex16("--salt", "00112233").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn hex16_rejects_invalid_hex() {
+        let err = hex16("--personal", "not hex at all!!").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_key_file_accepts_max_length() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0xaa; 64]).unwrap();
+        let key = read_key_file(file.path()).unwrap();
+        assert_eq!(key.len(), 64);
+    }
+
+    #[test]
+    fn read_key_file_rejects_oversized_key() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0xaa; 65]).unwrap();
+        let err = read_key_file(file.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    fn test_opt() -> Opt {
+        Opt {
+            input: Vec::new(),
+            length_bits: 512,
+            mmap: false,
+            parallel: false,
+            num_threads: None,
+            key_file: None,
+            salt: None,
+            personal: None,
+            buffer_size: None,
+        }
+    }
+
+    #[test]
+    fn parallel_threshold_is_a_closed_lower_bound() {
+        let opt = test_opt();
+        assert!(!should_parallelize(&opt, PARALLEL_THRESHOLD - 1));
+
+        let mut parallel_opt = test_opt();
+        parallel_opt.parallel = true;
+        assert!(!should_parallelize(&parallel_opt, PARALLEL_THRESHOLD - 1));
+        assert!(should_parallelize(&parallel_opt, PARALLEL_THRESHOLD));
+    }
+}